@@ -1,93 +1,213 @@
+use nix::sys::epoll::{Epoll, EpollCreateFlags, EpollEvent, EpollFlags, EpollTimeout};
 use nix::sys::mman::{mprotect, ProtFlags};
 use nix::unistd::{sysconf, SysconfVar};
 use std::alloc::{alloc, dealloc, Layout};
-use std::collections::{HashMap, HashSet, LinkedList};
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::ffi::c_void;
+use std::os::fd::RawFd;
+use std::panic::{self, AssertUnwindSafe};
 use std::ptr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use std::thread;
 
-extern "C" {
-    fn set_context(ctx: *mut Registers) -> u64;
-    fn switch_context(ctx: *const Registers) -> !;
-}
-
-#[repr(C)]
-struct Registers {
-    // callee保存レジスタ
-    d8: u64,
-    d9: u64,
-    d10: u64,
-    d11: u64,
-    d12: u64,
-    d13: u64,
-    d14: u64,
-    d15: u64,
-    x19: u64,
-    x20: u64,
-    x21: u64,
-    x22: u64,
-    x23: u64,
-    x24: u64,
-    x25: u64,
-    x26: u64,
-    x27: u64,
-    x28: u64,
-
-    // リンクレジスタ
-    x30: u64,
-    // スタックポインタ
-    sp: u64,
-}
-
-impl Registers {
-    fn new(sp: u64) -> Self {
-        Registers {
-            d8: 0,
-            d9: 0,
-            d10: 0,
-            d11: 0,
-            d12: 0,
-            d13: 0,
-            d14: 0,
-            d15: 0,
-            x19: 0,
-            x20: 0,
-            x21: 0,
-            x22: 0,
-            x23: 0,
-            x24: 0,
-            x25: 0,
-            x26: 0,
-            x27: 0,
-            x28: 0,
-            x30: entry_point as u64,
-            sp,
+// レジスタ退避レイアウトと`set_context`/`switch_context`の実装はアーキテクチャ
+// ごとに異なるため、この`arch`モジュール1つに閉じ込める｡スケジューラ本体からは
+// `arch::Registers`と`arch::{set_context, switch_context}`だけを使い、
+// 呼び出し側はターゲットアーキテクチャを意識しなくてよい
+mod arch {
+    #[cfg(target_arch = "aarch64")]
+    pub(crate) use aarch64::{switch_context, Registers};
+    #[cfg(target_arch = "aarch64")]
+    pub(crate) use aarch64::set_context;
+
+    #[cfg(target_arch = "aarch64")]
+    mod aarch64 {
+        extern "C" {
+            pub(crate) fn set_context(ctx: *mut Registers) -> u64;
+            pub(crate) fn switch_context(ctx: *const Registers) -> !;
+        }
+
+        #[repr(C)]
+        pub(crate) struct Registers {
+            // callee保存レジスタ
+            d8: u64,
+            d9: u64,
+            d10: u64,
+            d11: u64,
+            d12: u64,
+            d13: u64,
+            d14: u64,
+            d15: u64,
+            x19: u64,
+            x20: u64,
+            x21: u64,
+            x22: u64,
+            x23: u64,
+            x24: u64,
+            x25: u64,
+            x26: u64,
+            x27: u64,
+            x28: u64,
+
+            // リンクレジスタ(switch_context先でpcとしてロードされる)
+            x30: u64,
+            // スタックポインタ
+            sp: u64,
+        }
+
+        impl Registers {
+            // `entry`はコンテキスト初回起動時の飛び先(リンクレジスタの初期値)
+            pub(crate) fn new(sp: u64, entry: u64) -> Self {
+                Registers {
+                    d8: 0,
+                    d9: 0,
+                    d10: 0,
+                    d11: 0,
+                    d12: 0,
+                    d13: 0,
+                    d14: 0,
+                    d15: 0,
+                    x19: 0,
+                    x20: 0,
+                    x21: 0,
+                    x22: 0,
+                    x23: 0,
+                    x24: 0,
+                    x25: 0,
+                    x26: 0,
+                    x27: 0,
+                    x28: 0,
+                    x30: entry,
+                    sp,
+                }
+            }
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) use x86_64::{switch_context, Registers};
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) use x86_64::set_context;
+
+    #[cfg(target_arch = "x86_64")]
+    mod x86_64 {
+        use std::arch::global_asm;
+
+        extern "C" {
+            pub(crate) fn set_context(ctx: *mut Registers) -> u64;
+            pub(crate) fn switch_context(ctx: *const Registers) -> !;
+        }
+
+        // callee保存レジスタ(rbx, rbp, r12-r15)とrsp、戻りアドレスを
+        // `Registers`へ退避/復元するだけの最小限の実装｡AArch64版と同じ規約
+        // (初回は0を返し、switch_context経由で戻ってきたときは非0を返す)に従う
+        global_asm!(
+            r#"
+            .global set_context
+            set_context:
+                mov [rdi + 0],  rbx
+                mov [rdi + 8],  rbp
+                mov [rdi + 16], r12
+                mov [rdi + 24], r13
+                mov [rdi + 32], r14
+                mov [rdi + 40], r15
+                mov rax, [rsp]
+                mov [rdi + 48], rax
+                lea rax, [rsp + 8]
+                mov [rdi + 56], rax
+                xor eax, eax
+                ret
+
+            .global switch_context
+            switch_context:
+                mov rbx, [rdi + 0]
+                mov rbp, [rdi + 8]
+                mov r12, [rdi + 16]
+                mov r13, [rdi + 24]
+                mov r14, [rdi + 32]
+                mov r15, [rdi + 40]
+                mov rcx, [rdi + 48]
+                mov rsp, [rdi + 56]
+                mov rax, 1
+                jmp rcx
+            "#
+        );
+
+        #[repr(C)]
+        pub(crate) struct Registers {
+            // callee保存レジスタ
+            rbx: u64,
+            rbp: u64,
+            r12: u64,
+            r13: u64,
+            r14: u64,
+            r15: u64,
+
+            // 戻りアドレス(switch_context先でripとしてロードされる)
+            ret: u64,
+            // スタックポインタ
+            rsp: u64,
+        }
+
+        impl Registers {
+            // `entry`はコンテキスト初回起動時の飛び先(戻りアドレスの初期値)
+            pub(crate) fn new(sp: u64, entry: u64) -> Self {
+                Registers {
+                    rbx: 0,
+                    rbp: 0,
+                    r12: 0,
+                    r13: 0,
+                    r14: 0,
+                    r15: 0,
+                    ret: entry,
+                    // switch_contextは`call`ではなく`jmp`でentryへ飛ぶため、
+                    // `call`が行うはずのリターンアドレスのpushが発生しない｡
+                    // SysV ABIは関数入口で`rsp % 16 == 8`を要求するので、
+                    // ページ境界(`sp % 16 == 0`)のままでは8ズレてしまい、
+                    // 呼び出し先がSSEのアラインメント付きスピルで落ちる
+                    rsp: sp - 8,
+                }
+            }
         }
     }
+
+    #[cfg(not(any(target_arch = "aarch64", target_arch = "x86_64")))]
+    compile_error!("green: unsupported target_arch (supported: aarch64, x86_64)");
 }
 
-// スレッド開始時に実行する関数の型
-type Entry = fn();
+// スレッド開始時に実行する関数の型｡戻り値を持つコルーチン(spawn_with_result)も
+// 同じ型で表現できるよう、結果の受け渡しはクロージャのキャプチャ(共有スロット)側に
+// 任せている
+type Entry = Box<dyn FnOnce() + Send>;
 
 // コンテキスト
 struct Context {
-    regs: Registers,      // レジスタ
-    stack: *mut u8,       // スタック
-    stack_layout: Layout, // スタックレイアウト
-    entry: Entry,         // エントリポイント
-    id: u64,              // スレッドID
+    regs: arch::Registers,        // レジスタ
+    stack: *mut u8,         // スタック
+    stack_layout: Layout,   // スタックレイアウト
+    entry: Option<Entry>,   // エントリポイント(entry_point内で一度だけ取り出して実行する)
+    id: u64,                // スレッドID
+    // パニック時のペイロードをPANICSに記録するかどうか｡JoinHandleを持たない
+    // (spawn()で生成された)コルーチンの分まで記録すると、誰にも回収されずに
+    // プロセス終了までPANICSへ積まれ続けてしまうため、JoinHandleで
+    // join()できるコルーチン(spawn_with_result())だけをtrueにする
+    track_panic: bool,
 }
 
 impl Context {
     // レジスタ情報へのポインタを取得
-    fn get_regs_mut(&mut self) -> *mut Registers {
-        &mut self.regs as *mut Registers
+    fn get_regs_mut(&mut self) -> *mut arch::Registers {
+        &mut self.regs as *mut arch::Registers
     }
 
-    fn get_regs(&self) -> *const Registers {
-        &self.regs as *const Registers
+    fn get_regs(&self) -> *const arch::Registers {
+        &self.regs as *const arch::Registers
     }
 
-    fn new(func: Entry, stack_size: usize, id: u64) -> Self {
+    fn new(func: Entry, stack_size: usize, id: u64, track_panic: bool) -> Self {
         let page_size = sysconf(SysconfVar::PAGE_SIZE).unwrap().unwrap();
 
         // スタック領域の確保
@@ -105,7 +225,7 @@ impl Context {
         };
 
         // レジスタの初期化
-        let regs = Registers::new(stack as u64 + stack_size as u64);
+        let regs = arch::Registers::new(stack as u64 + stack_size as u64, entry_point as u64);
 
         // コンテキストの初期化
         Context {
@@ -113,255 +233,861 @@ impl Context {
             stack,
             id,
             stack_layout: layout,
-            entry: func,
+            entry: Some(func),
+            track_panic,
         }
     }
 }
 
-// すべてのスレッド終了時に戻ってくる先
-static mut CTX_MAIN: Option<Box<Registers>> = None;
+// `Context`は生ポインタ(スタック領域)を持つため自動では`Send`にならないが､
+// 実行キュー間を移動させる(work stealing)ときは必ずミューテックス配下で
+// かつコンテキストスイッチが起きる前に所有権ごと移動させるので安全に送れる
+struct StealableContext(Box<Context>);
+
+unsafe impl Send for StealableContext {}
+
+impl std::ops::Deref for StealableContext {
+    type Target = Context;
+    fn deref(&self) -> &Context {
+        &self.0
+    }
+}
+
+impl std::ops::DerefMut for StealableContext {
+    fn deref_mut(&mut self) -> &mut Context {
+        &mut self.0
+    }
+}
 
-// 不要なスタック領域
-static mut UNUSED_STACK: (*mut u8, Layout) = (ptr::null_mut(), Layout::new::<u8>());
+// ワーカー1つが持つ実行キュー｡前から取り出して実行し､他ワーカーからは
+// 後ろから半分だけ奪われる(work stealing)
+struct Worker {
+    queue: Mutex<VecDeque<StealableContext>>,
+}
+
+// 全ワーカー(1ワーカー = 1 OSスレッド)
+static WORKERS: OnceLock<Vec<Worker>> = OnceLock::new();
 
-// スレッドの実行キュー
-static mut CONTEXTS: LinkedList<Box<Context>> = LinkedList::new();
+// 生存しているグリーンスレッド(実行中、実行待ち、受信/join/fd待ちを含む)の総数
+// (生存しているものが無くなったかどうかと、recv/joinのデッドロック判定に使う)
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
 
-// スレッドIDの集合
-static mut ID: *mut HashSet<u64> = ptr::null_mut();
+// 一度に奪い合いを試みる回数
+const STEAL_ATTEMPTS: usize = 4;
+
+thread_local! {
+    // 自OSスレッド(ワーカー)がすべてのグリーンスレッド終了時に戻ってくる先
+    static CTX_MAIN: RefCell<Option<Box<arch::Registers>>> = RefCell::new(None);
+    // 自OSスレッドが担当するワーカー番号
+    static WORKER_ID: Cell<usize> = Cell::new(0);
+    // 自ワーカーの不要なスタック領域
+    static UNUSED_STACK: Cell<(*mut u8, Layout)> = Cell::new((ptr::null_mut(), Layout::new::<u8>()));
+}
+
+// スレッドIDの集合(複数ワーカーから触られるためロックで保護する)
+static ID: OnceLock<Mutex<HashSet<u64>>> = OnceLock::new();
+
+// entry_point内でcatch_unwindしたパニックのペイロードをidごとに記録しておく場所｡
+// JoinHandle::join()がここから取り出して呼び出し元に伝える
+static PANICS: OnceLock<Mutex<HashMap<u64, Box<dyn Any + Send>>>> = OnceLock::new();
 
 fn get_id() -> u64 {
+    let ids = ID.get().unwrap();
     loop {
         let rnd: u64 = rand::random();
-        unsafe {
-            if !(*ID).contains(&rnd) {
-                (*ID).insert(rnd);
-                return rnd;
-            }
-        };
+        let mut ids = ids.lock().unwrap();
+        if !ids.contains(&rnd) {
+            ids.insert(rnd);
+            return rnd;
+        }
     }
 }
 
-pub fn spawn(func: Entry, stack_size: usize) -> u64 {
-    unsafe {
-        let id = get_id();
-        CONTEXTS.push_back(Box::new(Context::new(func, stack_size, id)));
-        schedule();
-        id
+fn current_worker() -> usize {
+    WORKER_ID.with(|w| w.get())
+}
+
+// 現在実行中のグリーンスレッドのidを取得
+fn current_green_id() -> u64 {
+    let idx = current_worker();
+    WORKERS.get().unwrap()[idx].queue.lock().unwrap().front().unwrap().id
+}
+
+// もっとも負荷(キュー長)の低いワーカーを選ぶ
+fn least_loaded_worker(workers: &[Worker]) -> usize {
+    workers
+        .iter()
+        .enumerate()
+        .min_by_key(|(_, w)| w.queue.lock().unwrap().len())
+        .map(|(i, _)| i)
+        .unwrap()
+}
+
+pub fn spawn(func: fn(), stack_size: usize) -> u64 {
+    let workers = WORKERS.get().expect("spawn_from_main was not called");
+    let id = get_id();
+    let ctx = StealableContext(Box::new(Context::new(Box::new(func), stack_size, id, false)));
+
+    let target = least_loaded_worker(workers);
+    workers[target].queue.lock().unwrap().push_back(ctx);
+    ACTIVE.fetch_add(1, Ordering::SeqCst);
+
+    schedule();
+    id
+}
+
+// `func`を実行し、その戻り値(またはパニック)を`JoinHandle`経由で取り出せる
+// グリーンスレッドを生成する
+pub fn spawn_with_result<T>(
+    func: impl FnOnce() -> T + Send + 'static,
+    stack_size: usize,
+) -> JoinHandle<T>
+where
+    T: Send + 'static,
+{
+    let workers = WORKERS.get().expect("spawn_from_main was not called");
+    let id = get_id();
+
+    // 実行結果を書き込む共有スロット｡entry_point側のcatch_unwindがパニックを
+    // 捕まえた場合はPANICSに記録されるため、ここには正常終了時の値のみ入る
+    let slot: Arc<Mutex<Option<T>>> = Arc::new(Mutex::new(None));
+    let slot_for_entry = Arc::clone(&slot);
+    let wrapped: Entry = Box::new(move || {
+        let value = func();
+        *slot_for_entry.lock().unwrap() = Some(value);
+    });
+
+    let ctx = StealableContext(Box::new(Context::new(wrapped, stack_size, id, true)));
+    let target = least_loaded_worker(workers);
+    workers[target].queue.lock().unwrap().push_back(ctx);
+    ACTIVE.fetch_add(1, Ordering::SeqCst);
+
+    schedule();
+
+    JoinHandle { id, slot }
+}
+
+// 他ワーカーの実行キューの後ろ半分を奪って自分のキューに追加する｡
+// 奪えた場合はtrueを返す
+fn steal_work(workers: &[Worker], self_idx: usize) -> bool {
+    if workers.len() < 2 {
+        return false;
     }
+
+    for _ in 0..STEAL_ATTEMPTS {
+        let victim = rand::random::<usize>() % workers.len();
+        if victim == self_idx {
+            continue;
+        }
+
+        let mut victim_queue = workers[victim].queue.lock().unwrap();
+        let len = victim_queue.len();
+        if len < 2 {
+            continue;
+        }
+        let half = victim_queue.split_off(len / 2);
+        drop(victim_queue);
+
+        workers[self_idx].queue.lock().unwrap().extend(half);
+        return true;
+    }
+
+    false
 }
 
-pub fn schedule() {
-    unsafe {
-        // 実行可能なプロセスが自身のみであるため即座にリターン
-        if CONTEXTS.len() == 1 {
-            return;
+// 自ワーカーの実行可能なものが尽きたときに呼ぶ｡他ワーカーから奪えないか、
+// fdの準備完了を待っているコンテキストが起こせないか試し続け､
+// 生存しているグリーンスレッドが1つも無くなったら(ACTIVEが0)Noneを返す｡
+// 受信待ちやjoin待ちのスレッドが残っている間はNoneを返さずに試し続ける
+fn steal_or_wait(workers: &'static [Worker], self_idx: usize) -> Option<()> {
+    loop {
+        // 自分のキューがすでに埋まっていればそれを使う
+        // (reactorのpollが自分宛てのfdを起こしたかもしれない)
+        if workers[self_idx].queue.lock().unwrap().front().is_some() {
+            return Some(());
+        }
+        if steal_work(workers, self_idx) {
+            return Some(());
+        }
+        // 何も奪えなければ､fdの準備完了をしばらく待つ｡これ自体がブロッキングな
+        // 待ち時間になるので、busy-loopにはならない
+        if poll_io(reactor_poll_timeout()) {
+            continue;
+        }
+        if ACTIVE.load(Ordering::SeqCst) == 0 {
+            return None;
         }
+    }
+}
 
-        // 自身のコンテキストを実行キューの最後に移動
-        let mut ctx = CONTEXTS.pop_front().unwrap();
-        // レジスタ保存領域へのポインタを取得
-        let regs = ctx.get_regs_mut();
-        CONTEXTS.push_back(ctx);
-
-        //レジスタを保存
-        if set_context(regs) == 0 {
-            // 次のスレッドにコンテキストスイッチ
-            let next = CONTEXTS.front().unwrap();
-            switch_context(next.get_regs());
+pub fn schedule() {
+    let idx = current_worker();
+    let workers = WORKERS.get().unwrap();
+
+    {
+        let queue = workers[idx].queue.lock().unwrap();
+        if queue.len() <= 1 {
+            drop(queue);
+            // 奪えなくても自身の実行は継続できるので結果は無視してよい
+            steal_work(workers, idx);
         }
+    }
+
+    let mut queue = workers[idx].queue.lock().unwrap();
+    if queue.len() <= 1 {
+        return;
+    }
+
+    // 自身のコンテキストを実行キューの最後に移動
+    let mut ctx = queue.pop_front().unwrap();
+    // レジスタ保存領域へのポインタを取得
+    let regs = ctx.get_regs_mut();
+    queue.push_back(ctx);
+
+    let next_regs = queue.front().unwrap().get_regs();
+    drop(queue);
 
-        // 不要なスタック領域を削除
-        rm_unused_stack();
+    // レジスタを保存
+    if unsafe { arch::set_context(regs) } == 0 {
+        // 次のスレッドにコンテキストスイッチ
+        unsafe { arch::switch_context(next_regs) };
     }
+
+    // 不要なスタック領域を削除
+    rm_unused_stack();
 }
 
 extern "C" fn entry_point() {
-    unsafe {
-        // 指定されたエントリ関数を実行
-        let ctx = CONTEXTS.front().unwrap();
-        (ctx.entry)();
+    let idx = current_worker();
+    let workers = WORKERS.get().unwrap();
 
-        // 以降がスレッド終了時の後処理
+    // 指定されたエントリ関数を実行｡パニックしても他のグリーンスレッドを
+    // 巻き込まないようcatch_unwindで隔離する
+    let (entry, id, track_panic) = {
+        let mut queue = workers[idx].queue.lock().unwrap();
+        let front = queue.front_mut().unwrap();
+        (front.entry.take().unwrap(), front.id, front.track_panic)
+    };
 
-        // 自身のコンテキストを取り除く
-        let ctx = CONTEXTS.pop_front().unwrap();
+    if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(entry)) {
+        // JoinHandleで拾われうる(spawn_with_result()で生成された)コルーチン
+        // の分だけ記録する｡spawn()の素のコルーチンまで記録すると、誰にも
+        // 回収されずプロセス終了までPANICSに積まれ続けてしまう
+        if track_panic {
+            PANICS.get().unwrap().lock().unwrap().insert(id, payload);
+        }
+    }
 
-        // スレッドIDを削除
-        (*ID).remove(&ctx.id);
+    // 以降がスレッド終了時の後処理
 
-        // 不要なスタック領域として保存
-        // この段階で解放すると､以降のコードでスタックが使えなくなる
-        UNUSED_STACK = (ctx.stack, ctx.stack_layout);
+    // 自身のコンテキストを取り除く
+    let mut queue = workers[idx].queue.lock().unwrap();
+    let ctx = queue.pop_front().unwrap();
 
-        match CONTEXTS.front() {
-            Some(c) => {
-                // 次のスレッドにコンテキストスイッチ
-                switch_context(c.get_regs());
-            }
-            None => {
-                // すべてのスレッドが終了した場合､main関数にスレッドに戻る
-                if let Some(c) = &CTX_MAIN {
-                    switch_context(&**c as *const Registers);
-                }
-            }
-        };
-    }
+    // TLSスロットをデストラクタごと破棄する(IDを削除する前に行う)
+    cleanup_tls(ctx.id);
+
+    // スレッドIDの削除とjoin()待ちスレッドの起床をWAITINGのロック1つの
+    // 臨界区間にまとめる｡join()側も「idがまだ残っているか」の確認から
+    // WAITINGへの登録までを同じロックで保護しているため、この区間と
+    // 交差しても確認直後に起こされて空振りする競合(lost wakeup)が起きない
+    let mut waiting = WAITING.get().unwrap().lock().unwrap();
+    ID.get().unwrap().lock().unwrap().remove(&ctx.id);
+    ACTIVE.fetch_sub(1, Ordering::SeqCst);
+    wake_locked(&mut waiting, ctx.id);
+    drop(waiting);
+
+    // 不要なスタック領域として保存
+    // この段階で解放すると､以降のコードでスタックが使えなくなる
+    UNUSED_STACK.with(|u| u.set((ctx.stack, ctx.stack_layout)));
+
+    match queue.front() {
+        Some(c) => {
+            // 同じワーカーに次のスレッドがいればそれへコンテキストスイッチ
+            let regs = c.get_regs();
+            drop(queue);
+            unsafe { arch::switch_context(regs) };
+        }
+        None => {
+            drop(queue);
+            // 自ワーカーのキューが空になったので他のワーカーから奪うか、
+            // それも尽きていればこのワーカーを起動したOSスレッドに戻る
+            switch_to_next(workers, idx);
+        }
+    };
 
     panic!("entry_point");
 }
 
-pub fn spawn_from_main(func: Entry, stack_size: usize) {
-    unsafe {
-        // すでに初期化済みならエラーとする
-        if CTX_MAIN.is_some() {
-            panic!("spawn_from_main is called twice");
-        }
+pub fn spawn_from_main(func: fn(), stack_size: usize, num_workers: usize) {
+    if WORKERS.get().is_some() {
+        panic!("spawn_from_main is called twice");
+    }
+    assert!(num_workers >= 1, "num_workers must be at least 1");
 
-        // main関数用のコンテキストを生成
-        CTX_MAIN = Some(Box::new(Registers::new(0)));
-        if let Some(ctx) = &mut CTX_MAIN {
-            // グローバル変数を初期化
-            let mut msgs = MappedList::new();
-            MESSAGES = &mut msgs as *mut MappedList<u64>;
-
-            let mut wating = HashMap::new();
-            WAITING = &mut wating as *mut HashMap<u64, Box<Context>>;
-
-            let mut ids = HashSet::new();
-            ID = &mut ids as *mut HashSet<u64>;
-
-            // すべてのスレッド終了時の戻り先を保存
-            if set_context(&mut **ctx as *mut Registers) == 0 {
-                // 最初に起動するスレッドのコンテキストを生成して実行
-                CONTEXTS.push_back(Box::new(Context::new(func, stack_size, get_id())));
-                let first = CONTEXTS.front().unwrap();
-                switch_context(first.get_regs());
-            }
+    ID.set(Mutex::new(HashSet::new())).ok();
+    WAITING.set(Mutex::new(HashMap::new())).ok();
+    PANICS.set(Mutex::new(HashMap::new())).ok();
+    IO_WAITING.set(Mutex::new(HashMap::new())).ok();
+    REACTOR.set(Reactor::new()).ok();
+    TLS_KEYS.set(Mutex::new(HashMap::new())).ok();
+    TLS_VALUES.set(Mutex::new(HashMap::new())).ok();
+
+    let workers: Vec<Worker> = (0..num_workers)
+        .map(|_| Worker {
+            queue: Mutex::new(VecDeque::new()),
+        })
+        .collect();
+    WORKERS.set(workers).ok();
+    let workers = WORKERS.get().unwrap();
+
+    // 最初に起動するスレッドはワーカー0のキューに積んでおく
+    let id = get_id();
+    workers[0]
+        .queue
+        .lock()
+        .unwrap()
+        .push_back(StealableContext(Box::new(Context::new(
+            Box::new(func),
+            stack_size,
+            id,
+            false,
+        ))));
+    ACTIVE.fetch_add(1, Ordering::SeqCst);
+
+    // ワーカー1以降を別のOSスレッドとして起動する
+    let handles: Vec<_> = (1..num_workers)
+        .map(|idx| std::thread::spawn(move || run_worker(idx)))
+        .collect();
+
+    // メインのOSスレッドはワーカー0として動作する
+    run_worker(0);
+
+    for h in handles {
+        let _ = h.join();
+    }
+
+    // グローバル変数をクリア
+    WAITING.get().unwrap().lock().unwrap().clear();
+    ID.get().unwrap().lock().unwrap().clear();
+    PANICS.get().unwrap().lock().unwrap().clear();
+    IO_WAITING.get().unwrap().lock().unwrap().clear();
+    TLS_KEYS.get().unwrap().lock().unwrap().clear();
+    TLS_VALUES.get().unwrap().lock().unwrap().clear();
+}
+
+// 1つのワーカーに対応するOSスレッドのエントリポイント
+fn run_worker(idx: usize) {
+    WORKER_ID.with(|w| w.set(idx));
+    let workers = WORKERS.get().unwrap();
 
-            // 不要なスタックを解放
-            rm_unused_stack();
+    let mut ctx_main = Box::new(arch::Registers::new(0, 0));
+    // すべてのグリーンスレッド終了時の戻り先を保存
+    if unsafe { arch::set_context(&mut *ctx_main as *mut arch::Registers) } == 0 {
+        CTX_MAIN.with(|m| *m.borrow_mut() = Some(ctx_main));
 
-            // グローバル変数をクリア
-            CTX_MAIN = None;
-            CONTEXTS.clear();
-            MESSAGES = ptr::null_mut();
-            WAITING = ptr::null_mut();
-            ID = ptr::null_mut();
+        let regs = workers[idx]
+            .queue
+            .lock()
+            .unwrap()
+            .front()
+            .map(|c| c.get_regs());
+        let regs = regs.or_else(|| {
+            steal_or_wait(workers, idx)?;
+            Some(workers[idx].queue.lock().unwrap().front().unwrap().get_regs())
+        });
 
-            msgs.clear();
-            wating.clear();
-            ids.clear();
+        if let Some(regs) = regs {
+            // 最初に起動するスレッドのコンテキストへスイッチ
+            unsafe { arch::switch_context(regs) };
         }
     }
+
+    // 不要なスタックを解放
+    rm_unused_stack();
+    CTX_MAIN.with(|m| *m.borrow_mut() = None);
+}
+
+fn rm_unused_stack() {
+    UNUSED_STACK.with(|u| {
+        let (ptr, layout) = u.get();
+        if !ptr.is_null() {
+            unsafe {
+                // スタック領域の保護を解除
+                mprotect(
+                    ptr as *mut c_void,
+                    sysconf(SysconfVar::PAGE_SIZE).unwrap().unwrap() as usize,
+                    ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+                )
+                .unwrap();
+
+                // スタック領域解放
+                dealloc(ptr, layout);
+            }
+            u.set((ptr::null_mut(), Layout::new::<u8>()));
+        }
+    });
+}
+
+// 受信待ち・join待ちスレッドの集合｡元々どのワーカーのキューに属していたかも
+// 覚えておき､起床したときに同じワーカーへ戻す｡キーはrecv()ではチャネルごとに
+// 割り振られる番号、join()では待っている対象のグリーンスレッドid
+static WAITING: OnceLock<Mutex<HashMap<u64, (usize, StealableContext)>>> = OnceLock::new();
+
+// keyで待機しているスレッドがいれば、それを元いたワーカーの実行キューに戻す｡
+// 呼び出し元がすでにWAITINGのロックを持っている場合はこちらを使う
+fn wake_locked(waiting: &mut HashMap<u64, (usize, StealableContext)>, key: u64) {
+    if let Some((idx, ctx)) = waiting.remove(&key) {
+        WORKERS.get().unwrap()[idx].queue.lock().unwrap().push_back(ctx);
+    }
+}
+
+// keyで待機しているスレッドがいれば、それを元いたワーカーの実行キューに戻す
+fn wake(key: u64) {
+    wake_locked(&mut WAITING.get().unwrap().lock().unwrap(), key);
 }
 
-unsafe fn rm_unused_stack() {
-    if !UNUSED_STACK.0.is_null() {
-        // スタック領域の保護を解除
-        mprotect(
-            UNUSED_STACK.0 as *mut c_void,
-            sysconf(SysconfVar::PAGE_SIZE).unwrap().unwrap() as usize,
-            ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
-        )
-        .unwrap();
+// グリーンスレッドが待ちたいfdの種類
+pub enum Interest {
+    Readable,
+    Writable,
+}
 
-        // スタック領域解放
-        dealloc(UNUSED_STACK.0, UNUSED_STACK.1);
-        UNUSED_STACK = (ptr::null_mut(), Layout::new::<u8>());
+impl Interest {
+    fn flags(&self) -> EpollFlags {
+        match self {
+            // ONESHOTにしておき、1度の通知ごとに毎回register()し直す
+            Interest::Readable => EpollFlags::EPOLLIN | EpollFlags::EPOLLONESHOT,
+            Interest::Writable => EpollFlags::EPOLLOUT | EpollFlags::EPOLLONESHOT,
+        }
     }
 }
 
-struct MappedList<T> {
-    map: HashMap<u64, LinkedList<T>>,
+// epoll(7)を使ったイベントループ｡スケジューラがアイドルになったときに
+// poll()されることで、ブロッキングI/Oを待つ間も他のグリーンスレッドを
+// 実行し続けられる
+struct Reactor {
+    epoll: Epoll,
+    // すでにepollに登録済みのfd(MOD/ADDのどちらを呼ぶべきか判別するため)
+    registered: Mutex<HashSet<RawFd>>,
 }
 
-impl<T> MappedList<T> {
+impl Reactor {
     fn new() -> Self {
-        MappedList {
-            map: HashMap::new(),
+        Reactor {
+            epoll: Epoll::new(EpollCreateFlags::empty()).expect("failed to create epoll instance"),
+            registered: Mutex::new(HashSet::new()),
         }
     }
 
-    // keyに対応するリストの最後尾に追加
-    fn push_back(&mut self, key: u64, val: T) {
-        if let Some(list) = self.map.get_mut(&key) {
-            // 対応するリストが存在するなら追加
-            list.push_back(val);
+    fn register(&self, fd: RawFd, interest: Interest) {
+        let mut event = EpollEvent::new(interest.flags(), fd as u64);
+        let mut registered = self.registered.lock().unwrap();
+        let fd_ref = unsafe { std::os::fd::BorrowedFd::borrow_raw(fd) };
+        if registered.contains(&fd) {
+            self.epoll.modify(fd_ref, &mut event).expect("epoll_ctl MOD failed");
         } else {
-            // 存在しない場合新たにリストを作成して追加
-            let mut list = LinkedList::new();
-            list.push_back(val);
-            self.map.insert(key, list);
+            self.epoll.add(fd_ref, event).expect("epoll_ctl ADD failed");
+            registered.insert(fd);
         }
     }
 
-    // keyに対応するリストの一番前から取り出す
-    fn pop_front(&mut self, key: u64) -> Option<T> {
-        if let Some(list) = self.map.get_mut(&key) {
-            let val = list.pop_front();
-            if list.is_empty() {
-                self.map.remove(&key);
-            }
-            return val;
+    // fdの監視を止める｡呼ばれないまま放置すると、fdをクローズした後にOSが
+    // 同じfd番号を別用途へ再利用したとき、無関係なfdの準備完了がこの古い
+    // 登録に紐付いてしまう｡呼び出し元がfdをクローズした後に呼ぶこともある
+    // ため、epoll_ctl(DEL)の失敗(EBADF等)は無視する
+    fn unregister(&self, fd: RawFd) {
+        let mut registered = self.registered.lock().unwrap();
+        if registered.remove(&fd) {
+            let fd_ref = unsafe { std::os::fd::BorrowedFd::borrow_raw(fd) };
+            let _ = self.epoll.delete(fd_ref);
         }
-        None
     }
 
-    fn clear(&mut self) {
-        self.map.clear();
+    // timeoutだけ待ち、準備ができたfdの一覧を返す
+    fn poll(&self, timeout: EpollTimeout) -> Vec<RawFd> {
+        let mut events = [EpollEvent::empty(); 64];
+        let n = self.epoll.wait(&mut events, timeout).unwrap_or(0);
+        events[..n].iter().map(|e| e.data() as RawFd).collect()
     }
 }
 
-// メッセージキュー
-static mut MESSAGES: *mut MappedList<u64> = ptr::null_mut();
+// fdの準備完了を待っているグリーンスレッドの集合｡どのワーカーから
+// 手放されたかも覚えておき、起床時に同じワーカーへ戻す
+static IO_WAITING: OnceLock<Mutex<HashMap<RawFd, (usize, StealableContext)>>> = OnceLock::new();
 
-// 待機スレッド集合
-static mut WAITING: *mut HashMap<u64, Box<Context>> = ptr::null_mut();
+static REACTOR: OnceLock<Reactor> = OnceLock::new();
 
-pub fn send(key: u64, msg: u64) {
-    unsafe {
-        // メッセージキューの最後尾に追加
-        (*MESSAGES).push_back(key, msg);
+// ワーカーがアイドルになったときにreactorのpollへ渡すタイムアウト｡
+// 短めにして、他ワーカーから奪えるものが無いかも定期的に試せるようにする
+fn reactor_poll_timeout() -> EpollTimeout {
+    EpollTimeout::from(10u16)
+}
+
+// reactorをtimeoutだけpollし、準備ができたfdを待っていたコンテキストを
+// 元のワーカーの実行キューに戻す｡何か1つでも起こせたらtrueを返す
+fn poll_io(timeout: EpollTimeout) -> bool {
+    let ready = REACTOR.get().unwrap().poll(timeout);
+    if ready.is_empty() {
+        return false;
+    }
 
-        // スレッドが受信待ちの場合に実行キューに移動
-        if let Some(ctx) = (*WAITING).remove(&key) {
-            CONTEXTS.push_back(ctx);
+    let mut io_waiting = IO_WAITING.get().unwrap().lock().unwrap();
+    let workers = WORKERS.get().unwrap();
+    let mut woke_any = false;
+    for fd in ready {
+        if let Some((idx, ctx)) = io_waiting.remove(&fd) {
+            workers[idx].queue.lock().unwrap().push_back(ctx);
+            woke_any = true;
         }
+    }
+    woke_any
+}
+
+// 現在実行中のグリーンスレッドを、fdの準備ができるまで待機させる
+pub fn wait_fd(fd: RawFd, interest: Interest) {
+    let reactor = REACTOR.get().expect("spawn_from_main was not called");
+    let idx = current_worker();
+    let workers = WORKERS.get().unwrap();
+
+    let mut queue = workers[idx].queue.lock().unwrap();
+    let mut ctx = queue.pop_front().unwrap();
+    let regs = ctx.get_regs_mut();
+    drop(queue);
+
+    // IO_WAITINGへの登録はreactorへの登録より先に行う｡逆順だと、登録直後に
+    // fdがreadyになったpoll_io()がIO_WAITINGにまだ何も無い状態でそれを
+    // 観測してしまい、EPOLLONESHOTのため二度とこのfdの準備完了が通知されず
+    // このコンテキストが永久に待ち続けることになる
+    IO_WAITING.get().unwrap().lock().unwrap().insert(fd, (idx, ctx));
+    reactor.register(fd, interest);
+
+    // 次の実行可能なスレッドにコンテキストスイッチ
+    if unsafe { arch::set_context(regs) } == 0 {
+        switch_to_next(workers, idx);
+    }
+
+    // 不要なスタックを削除
+    rm_unused_stack();
+}
+
+// fdの監視をやめる｡wait_fdで待っていたコルーチンが用済みになった後、
+// 特にそのfdをクローズするより前に呼び出して、reactorに古い登録を
+// 残さないようにする
+pub fn unregister_fd(fd: RawFd) {
+    let reactor = REACTOR.get().expect("spawn_from_main was not called");
+    reactor.unregister(fd);
+}
+
+// 自ワーカーの次に実行可能なコンテキストへスイッチする｡自ワーカーに無ければ
+// 他ワーカーから奪うか、奪えるものが尽きていればこのワーカーを起動した
+// OSスレッドに戻る
+fn switch_to_next(workers: &'static [Worker], idx: usize) {
+    let next = workers[idx].queue.lock().unwrap().front().map(|c| c.get_regs());
+    let next = next.or_else(|| {
+        steal_or_wait(workers, idx)?;
+        Some(workers[idx].queue.lock().unwrap().front().unwrap().get_regs())
+    });
+
+    match next {
+        Some(regs) => unsafe { arch::switch_context(regs) },
+        None => CTX_MAIN.with(|m| {
+            if let Some(c) = &*m.borrow() {
+                unsafe { arch::switch_context(&**c as *const arch::Registers) };
+            }
+        }),
+    }
+}
+
+// 実行中のグリーンスレッドをkeyで待機状態にし、次の実行可能なスレッドへ
+// コンテキストスイッチする｡送信側がwake(key)するまで戻ってこない｡
+//
+// 呼び出し元は「まだ条件が満たされていないか」の確認から、このWAITINGへの
+// 登録までの間ロックを手放さずに済むよう、あらかじめ獲得したWAITINGの
+// ロックをそのまま渡す｡こうすることで、確認直後に送信側のwake(key)が
+// 割り込んで空振りする競合(lost wakeup)を防げる｡登録が終わり次第
+// このロックは手放す
+fn park_current(key: u64, mut waiting: MutexGuard<'_, HashMap<u64, (usize, StealableContext)>>) {
+    let idx = current_worker();
+    let workers = WORKERS.get().unwrap();
+
+    let mut queue = workers[idx].queue.lock().unwrap();
+    let mut ctx = queue.pop_front().unwrap();
+    let regs = ctx.get_regs_mut();
+    drop(queue);
+    waiting.insert(key, (idx, ctx));
+    drop(waiting);
+
+    // 次の実行可能なスレッドにコンテキストスイッチ
+    if unsafe { arch::set_context(regs) } == 0 {
+        switch_to_next(workers, idx);
+    }
+
+    // 不要なスタックを削除
+    rm_unused_stack();
+}
+
+// チャネルごとに割り振る一意なキー(WAITINGへの登録・検索に使う)
+static NEXT_CHANNEL_KEY: AtomicU64 = AtomicU64::new(0);
+
+fn next_channel_key() -> u64 {
+    NEXT_CHANNEL_KEY.fetch_add(1, Ordering::Relaxed)
+}
+
+// チャネルの実体｡Sender/Receiverの両方から参照され、両方がドロップされたら
+// 一緒に解放される
+struct ChannelInner<T> {
+    queue: Mutex<VecDeque<T>>,
+}
+
+pub struct Sender<T> {
+    key: u64,
+    inner: Arc<ChannelInner<T>>,
+}
+
+pub struct Receiver<T> {
+    key: u64,
+    inner: Arc<ChannelInner<T>>,
+}
+
+// 任意の型Tを運べるグリーンスレッド間チャネルを作る
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let key = next_channel_key();
+    let inner = Arc::new(ChannelInner {
+        queue: Mutex::new(VecDeque::new()),
+    });
+
+    (
+        Sender {
+            key,
+            inner: inner.clone(),
+        },
+        Receiver { key, inner },
+    )
+}
+
+impl<T> Sender<T> {
+    pub fn send(&self, val: T) {
+        // メッセージキューの最後尾に追加
+        self.inner.queue.lock().unwrap().push_back(val);
+
+        // 受信側が待機中なら起こす
+        wake(self.key);
         schedule();
     }
 }
 
-pub fn recv() -> Option<u64> {
-    unsafe {
-        // スレッドIDを取得
-        let key = CONTEXTS.front().unwrap().id;
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        Sender {
+            key: self.key,
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T> Receiver<T> {
+    pub fn recv(&self) -> Option<T> {
+        loop {
+            // メッセージがすでにキューにある場合即座にリターン
+            if let Some(val) = self.inner.queue.lock().unwrap().pop_front() {
+                return Some(val);
+            }
+
+            // システム全体で実行可能/生存中のスレッドが自身のみの場合はデッドロック
+            if ACTIVE.load(Ordering::SeqCst) == 1 {
+                panic!("deadlock");
+            }
+
+            // WAITINGのロックを保持したままもう一度キューを確認し、空であれば
+            // そのまま受信待ち状態への登録まで行う｡send()側のwake(key)も
+            // このロックを取ってから動くため、確認と登録の間にpush_back+wake()
+            // が割り込んで空振りする競合(lost wakeup)は起きない
+            let waiting = WAITING.get().unwrap().lock().unwrap();
+            if let Some(val) = self.inner.queue.lock().unwrap().pop_front() {
+                return Some(val);
+            }
+            park_current(self.key, waiting);
+        }
+    }
+}
+
+// `spawn_with_result`が返すハンドル｡対象のグリーンスレッドの終了を待ち、
+// 戻り値(またはパニックのペイロード)を受け取る
+pub struct JoinHandle<T> {
+    id: u64,
+    slot: Arc<Mutex<Option<T>>>,
+}
 
-        // メッセージがすでにキューにある場合即座にリターン
-        if let Some(msg) = (*MESSAGES).pop_front(key) {
-            return Some(msg);
+impl<T> JoinHandle<T> {
+    // 対象のグリーンスレッドが終了するまで呼び出し元を待機させ、その結果を返す
+    pub fn join(self) -> thread::Result<T> {
+        loop {
+            // 正常終了していれば結果を、パニックしていればそのペイロードを返す
+            if let Some(value) = self.slot.lock().unwrap().take() {
+                return Ok(value);
+            }
+            if let Some(payload) = PANICS.get().unwrap().lock().unwrap().remove(&self.id) {
+                return Err(payload);
+            }
+
+            // まだ終了していなければ、起こされるまで待機する｡WAITINGのロックを
+            // 保持したまま「idがまだ残っているか」を確認し、そのまま待機登録
+            // (park_current)まで行うことで、entry_point側の終了処理(IDの
+            // 削除とwake)との間のロスト・ウェイクアップを防ぐ
+            let waiting = WAITING.get().unwrap().lock().unwrap();
+            if !ID.get().unwrap().lock().unwrap().contains(&self.id) {
+                // idが消えているのに結果もパニックも記録されていないことは
+                // 起こらないはずだが、念のためループを継続する
+                drop(waiting);
+                continue;
+            }
+            if ACTIVE.load(Ordering::SeqCst) == 1 {
+                panic!("deadlock");
+            }
+            park_current(self.id, waiting);
         }
+    }
+}
 
-        // 実行可能なスレッドが他にいない場合はデッドロック
-        if CONTEXTS.len() == 1 {
-            panic!("deadlock");
+// pthreadのキー付きTLSを模した、グリーンスレッドローカルストレージ用のキー
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Key(u64);
+
+// キーごとに登録されたデストラクタ｡キー自体はプロセス全体で共有される
+static TLS_KEYS: OnceLock<Mutex<HashMap<u64, Option<fn(usize)>>>> = OnceLock::new();
+
+static NEXT_TLS_KEY: AtomicU64 = AtomicU64::new(0);
+
+// グリーンスレッドid -> (キー -> 値)｡各グリーンスレッドのスロットは
+// そのグリーンスレッドの終了時にentry_point側で破棄される
+static TLS_VALUES: OnceLock<Mutex<HashMap<u64, HashMap<u64, usize>>>> = OnceLock::new();
+
+// 新しいTLSキーを作る｡`dtor`を指定すると、そのキーに値を持ったまま
+// グリーンスレッドが終了したときに`dtor(値)`が呼ばれる
+pub fn tls_create(dtor: Option<fn(usize)>) -> Key {
+    let key = NEXT_TLS_KEY.fetch_add(1, Ordering::Relaxed);
+    TLS_KEYS.get().unwrap().lock().unwrap().insert(key, dtor);
+    Key(key)
+}
+
+// 現在実行中のグリーンスレッドのkeyに対応するスロットへ値を書き込む
+pub fn tls_set(key: Key, value: usize) {
+    let id = current_green_id();
+    TLS_VALUES
+        .get()
+        .unwrap()
+        .lock()
+        .unwrap()
+        .entry(id)
+        .or_default()
+        .insert(key.0, value);
+}
+
+// 現在実行中のグリーンスレッドのkeyに対応するスロットから値を読み出す
+pub fn tls_get(key: Key) -> Option<usize> {
+    let id = current_green_id();
+    TLS_VALUES
+        .get()
+        .unwrap()
+        .lock()
+        .unwrap()
+        .get(&id)
+        .and_then(|slots| slots.get(&key.0).copied())
+}
+
+// idのグリーンスレッドが持つTLSスロットをすべて破棄する｡
+// 登録されたデストラクタがあればそれぞれ呼び出してから取り除く
+fn cleanup_tls(id: u64) {
+    let slots = TLS_VALUES.get().unwrap().lock().unwrap().remove(&id);
+    let Some(slots) = slots else {
+        return;
+    };
+
+    let dtors = TLS_KEYS.get().unwrap().lock().unwrap();
+    for (key, value) in slots {
+        if let Some(Some(dtor)) = dtors.get(&key) {
+            dtor(value);
         }
+    }
+}
 
-        // 実行中のスレッドを受信待ち状態に移行
-        let mut ctx = CONTEXTS.pop_front().unwrap();
-        let regs = ctx.get_regs_mut();
-        (*WAITING).insert(key, ctx);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        // 次の実行可能なスレッドにコンテキストスイッチ
-        if set_context(regs) == 0 {
-            let next = CONTEXTS.front().unwrap();
-            switch_context((**next).get_regs());
+    // steal_work()が相手のキューの後ろ半分を奪えること、また相手のキューが
+    // 1件以下のときは何も奪わないことを確認する
+    #[test]
+    fn steal_work_splits_victim_queue_in_half() {
+        let workers: Vec<Worker> = (0..2)
+            .map(|_| Worker {
+                queue: Mutex::new(VecDeque::new()),
+            })
+            .collect();
+
+        for id in 0..6 {
+            workers[1].queue.lock().unwrap().push_back(StealableContext(
+                Box::new(Context::new(Box::new(|| {}), 1 << 16, id, false)),
+            ));
+        }
+
+        // 奪う相手はランダムに選ばれるため、成功するまで繰り返す
+        // (ワーカーは2つしかないので、自分以外はもう片方に固定される)
+        let mut stole = false;
+        for _ in 0..50 {
+            if steal_work(&workers, 0) {
+                stole = true;
+                break;
+            }
         }
+        assert!(stole);
+        assert_eq!(workers[0].queue.lock().unwrap().len(), 3);
+        assert_eq!(workers[1].queue.lock().unwrap().len(), 3);
+
+        // 相手に1件しか残っていない状態からは奪えない
+        workers[0].queue.lock().unwrap().clear();
+        workers[1].queue.lock().unwrap().pop_back();
+        workers[1].queue.lock().unwrap().pop_back();
+        assert_eq!(workers[1].queue.lock().unwrap().len(), 1);
+        assert!(!steal_work(&workers, 0));
+    }
+
+    // テスト用にコルーチン間でSenderを受け渡すためだけの置き場｡
+    // `spawn`は`fn()`しか受け取れずクロージャをキャプチャできないため必要
+    static TEST_SENDER: OnceLock<Mutex<Option<Sender<u64>>>> = OnceLock::new();
+    static TEST_RESULT: OnceLock<Mutex<Option<(u64, u64)>>> = OnceLock::new();
+
+    fn test_sender_entry() {
+        let sender = TEST_SENDER.get().unwrap().lock().unwrap().take().unwrap();
+        sender.send(42);
+    }
+
+    fn test_main_entry() {
+        let (tx, rx) = channel::<u64>();
+        TEST_SENDER.set(Mutex::new(Some(tx))).ok();
+        spawn(test_sender_entry, 1 << 16);
+        let recv_val = rx.recv().unwrap_or(0);
+
+        let handle = spawn_with_result(|| 7u64, 1 << 16);
+        let join_val = handle.join().unwrap_or(0);
+
+        // アサーションはこの関数自身の中では行わない｡ここはグリーン
+        // スレッドとして(entry_pointのcatch_unwind配下で)実行されるため、
+        // assert!がパニックしても呼び出し元のOSスレッドには伝わらず、
+        // テストが誤って成功してしまう
+        TEST_RESULT.set(Mutex::new(Some((recv_val, join_val)))).ok();
+    }
 
-        // 不要なスタックを削除
-        rm_unused_stack();
+    // 複数ワーカーにまたがってもchannelのsend/recvとJoinHandle::joinが
+    // きちんと完了することを確認する｡どちらも以前は送信側/終了側とのタイミング
+    // 次第で永久に止まりうるロスト・ウェイクアップのバグがあった
+    #[test]
+    fn channel_and_join_round_trip_across_workers() {
+        spawn_from_main(test_main_entry, 1 << 16, 2);
 
-        // 受信したメッセージを取得
-        (*MESSAGES).pop_front(key)
+        let (recv_val, join_val) = TEST_RESULT.get().unwrap().lock().unwrap().take().unwrap();
+        assert_eq!(recv_val, 42);
+        assert_eq!(join_val, 7);
     }
 }